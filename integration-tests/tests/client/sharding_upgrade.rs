@@ -1,4 +1,5 @@
-use borsh::BorshSerialize;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::Serialize;
 
 use crate::process_blocks::{create_nightshade_runtimes, set_block_protocol_version};
 use near_chain::{ChainGenesis, Provenance};
@@ -29,6 +30,76 @@ use std::collections::{HashMap, HashSet};
 const SIMPLE_NIGHTSHADE_PROTOCOL_VERSION: ProtocolVersion =
     ProtocolFeature::SimpleNightshade.protocol_version();
 
+/// One piece of split-shard state sent to a late-joining node; `format_version` lets old and new
+/// chunk layouts coexist.
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+struct SplitShardSnapshotChunk {
+    format_version: u8,
+    shard_uid: ShardUId,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+const SPLIT_SHARD_SNAPSHOT_FORMAT_VERSION: u8 = 1;
+/// Number of trie entries per chunk, so each chunk decodes and applies independently.
+const SPLIT_SHARD_SNAPSHOT_CHUNK_SIZE: usize = 500;
+
+/// Walks the trie for `shard_uid` at `state_root` on `client_idx`, slicing it into a sequence of
+/// [`SplitShardSnapshotChunk`]s.
+fn produce_split_shard_snapshot(
+    env: &mut TestEnv,
+    client_idx: usize,
+    shard_uid: ShardUId,
+    state_root: CryptoHash,
+) -> Vec<SplitShardSnapshotChunk> {
+    let tries = env.clients[client_idx].runtime_adapter.get_tries();
+    let trie = tries.get_trie_for_shard(shard_uid, state_root);
+    let mut chunks = vec![];
+    let mut entries = vec![];
+    for item in trie.iter().unwrap() {
+        entries.push(item.unwrap());
+        if entries.len() == SPLIT_SHARD_SNAPSHOT_CHUNK_SIZE {
+            chunks.push(SplitShardSnapshotChunk {
+                format_version: SPLIT_SHARD_SNAPSHOT_FORMAT_VERSION,
+                shard_uid,
+                entries: std::mem::take(&mut entries),
+            });
+        }
+    }
+    if !entries.is_empty() {
+        chunks.push(SplitShardSnapshotChunk {
+            format_version: SPLIT_SHARD_SNAPSHOT_FORMAT_VERSION,
+            shard_uid,
+            entries,
+        });
+    }
+    chunks
+}
+
+/// Rebuilds a shard's trie incrementally from snapshot chunks into `client_idx`'s store and
+/// returns the resulting state root. Chunks are applied one at a time, in any order.
+fn restore_split_shard_snapshot(
+    env: &mut TestEnv,
+    client_idx: usize,
+    chunks: &[SplitShardSnapshotChunk],
+) -> CryptoHash {
+    let tries = env.clients[client_idx].runtime_adapter.get_tries();
+    let mut state_root = CryptoHash::default();
+    for chunk in chunks {
+        assert_eq!(
+            chunk.format_version, SPLIT_SHARD_SNAPSHOT_FORMAT_VERSION,
+            "restoring node does not understand this split shard snapshot chunk format",
+        );
+        let trie = tries.get_trie_for_shard(chunk.shard_uid, state_root);
+        let trie_changes = trie
+            .update(chunk.entries.iter().map(|(key, value)| (key.clone(), Some(value.clone()))))
+            .unwrap();
+        let mut store_update = tries.store_update();
+        state_root = tries.apply_all(&trie_changes, chunk.shard_uid, &mut store_update);
+        store_update.commit().unwrap();
+    }
+    state_root
+}
+
 struct TestShardUpgradeEnv {
     env: TestEnv,
     initial_accounts: Vec<AccountId>,
@@ -37,13 +108,21 @@ struct TestShardUpgradeEnv {
     epoch_length: u64,
     num_validators: usize,
     num_clients: usize,
+    shard_layout_activation_epoch: u64,
+    shard_layout: ShardLayout,
+    skip_account_checks: bool,
 }
 
-/// Test shard layout upgrade. This function runs `env` to produce and process blocks
-/// from 1 to 3 * epoch_length + 1, ie, to the beginning of epoch 3.
-/// Epoch 0: 1 shard
-/// Epoch 1: 1 shard, state split happens
-/// Epoch 2: shard layout upgrades to simple_night_shade_shard,
+/// Drives `env` through a single shard layout transition:
+/// Epoch 0: genesis layout
+/// Epoch `shard_layout_activation_epoch - 1`: state split happens for the upcoming layout
+/// Epoch `shard_layout_activation_epoch`: the new layout is in effect
+///
+/// `shard_layout_activation_epoch` and `shard_layout` are parameters rather than hardcoded
+/// constants, so callers can exercise a shard layout other than `simple_nightshade_shard_layout`
+/// (a different split boundary, a different number of children, etc). `GenesisConfig` only
+/// carries a single post-genesis shard layout (see `setup_genesis`), so chaining a second
+/// transition isn't supported here.
 impl TestShardUpgradeEnv {
     fn new(
         epoch_length: u64,
@@ -51,6 +130,8 @@ impl TestShardUpgradeEnv {
         num_clients: usize,
         num_init_accounts: usize,
         gas_limit: Option<u64>,
+        shard_layout_activation_epoch: u64,
+        shard_layout: ShardLayout,
     ) -> Self {
         let mut rng = thread_rng();
         let validators: Vec<AccountId> = (0..num_validators)
@@ -58,8 +139,13 @@ impl TestShardUpgradeEnv {
             .collect();
         let initial_accounts =
             [validators, gen_unique_accounts(&mut rng, num_init_accounts)].concat();
-        let genesis =
-            setup_genesis(epoch_length, num_validators as u64, initial_accounts.clone(), gas_limit);
+        let genesis = setup_genesis(
+            epoch_length,
+            num_validators as u64,
+            initial_accounts.clone(),
+            gas_limit,
+            &shard_layout,
+        );
         let chain_genesis = ChainGenesis::from(&genesis);
         let env = TestEnv::builder(chain_genesis)
             .clients_count(num_clients)
@@ -72,6 +158,9 @@ impl TestShardUpgradeEnv {
             epoch_length,
             num_validators,
             num_clients,
+            shard_layout_activation_epoch,
+            shard_layout,
+            skip_account_checks: false,
             init_txs: vec![],
             txs_by_height: HashMap::new(),
         }
@@ -88,8 +177,15 @@ impl TestShardUpgradeEnv {
         self.txs_by_height.insert(height, txs);
     }
 
+    /// Skips the per-account `check_account` pass `step` normally runs; lets benchmarks time a
+    /// step without its cost dominated by an O(num_accounts) verification loop.
+    fn set_skip_account_checks(&mut self, skip: bool) {
+        self.skip_account_checks = skip;
+    }
+
     /// produces and processes the next block
     /// also checks that all accounts in initial_accounts are intact
+    /// (that check is skipped while `skip_account_checks` is set)
     fn step(&mut self) {
         let env = &mut self.env;
         let mut rng = thread_rng();
@@ -130,7 +226,11 @@ impl TestShardUpgradeEnv {
         set_block_protocol_version(
             &mut block,
             block_producer.clone(),
-            SIMPLE_NIGHTSHADE_PROTOCOL_VERSION,
+            protocol_version_for_height(
+                self.shard_layout_activation_epoch,
+                self.epoch_length,
+                height,
+            ),
         );
         // make sure that catchup is done before the end of each epoch, but when it is done is
         // by chance. This simulates when catchup takes a long time to be done
@@ -148,8 +248,65 @@ impl TestShardUpgradeEnv {
         env.process_partial_encoded_chunks();
 
         // after state split, check chunk extra exists and the states are correct
-        for account_id in self.initial_accounts.iter() {
-            check_account(env, account_id, &block);
+        if !self.skip_account_checks {
+            for account_id in self.initial_accounts.iter() {
+                check_account(env, account_id, &block);
+            }
+        }
+    }
+
+    /// Brings a brand-new client (genesis only) up to date on the post-split shards via snapshot
+    /// chunks rather than by reprocessing history, and checks its restored state roots against
+    /// `get_chunk_extra` on a client that tracked the split live. `mid_epoch` asserts that the
+    /// head is not the last block of an epoch, the block at which `step` always runs catchup.
+    fn add_late_client(&mut self, mid_epoch: bool) {
+        let head = self.env.clients[0].chain.head().unwrap();
+        if mid_epoch {
+            assert_ne!(
+                head.height % self.epoch_length,
+                0,
+                "mid_epoch snapshot must not be taken at the last block of an epoch"
+            );
+        }
+        let block = self.env.clients[0].chain.get_block(&head.last_block_hash).unwrap().clone();
+        let shard_layout = self.env.clients[0]
+            .runtime_adapter
+            .get_shard_layout_from_prev_block(block.header().prev_hash())
+            .unwrap();
+
+        // the late client never processes a single block; all it has is genesis
+        let genesis = setup_genesis(
+            self.epoch_length,
+            self.num_validators as u64,
+            self.initial_accounts.clone(),
+            None,
+            &self.shard_layout,
+        );
+        let chain_genesis = ChainGenesis::from(&genesis);
+        let mut late_env = TestEnv::builder(chain_genesis)
+            .clients_count(1)
+            .runtime_adapters(create_nightshade_runtimes(&genesis, 1))
+            .build();
+
+        for shard_id in shard_layout.shard_ids() {
+            let shard_uid = ShardUId::from_shard_id_and_layout(shard_id, &shard_layout);
+            let expected_state_root = self.env.clients[0]
+                .chain
+                .get_chunk_extra(block.hash(), &shard_uid)
+                .unwrap()
+                .state_root()
+                .clone();
+
+            let chunks =
+                produce_split_shard_snapshot(&mut self.env, 0, shard_uid, expected_state_root);
+            let restored_state_root = restore_split_shard_snapshot(&mut late_env, 0, &chunks);
+
+            assert_eq!(
+                restored_state_root, expected_state_root,
+                "late-joining client's restored state root for {:?} does not match the state \
+                 root computed by a client that tracked the split live",
+                shard_uid,
+            );
         }
     }
 
@@ -263,11 +420,52 @@ fn check_account(env: &mut TestEnv, account_id: &AccountId, block: &Block) {
     }
 }
 
+/// The protocol version a block at `height` should declare. The transition needs a full epoch of
+/// voted blocks before epoch_manager can act on it (the state split itself happens during
+/// `shard_layout_activation_epoch - 1`), so its version must already be revealed by the start of
+/// `shard_layout_activation_epoch - 2` — matching how `SIMPLE_NIGHTSHADE_PROTOCOL_VERSION` was
+/// voted for from block height 1 onward here originally.
+fn protocol_version_for_height(
+    shard_layout_activation_epoch: u64,
+    epoch_length: u64,
+    height: u64,
+) -> ProtocolVersion {
+    let reveal_epoch = shard_layout_activation_epoch.saturating_sub(2);
+    if height > reveal_epoch * epoch_length {
+        SIMPLE_NIGHTSHADE_PROTOCOL_VERSION
+    } else {
+        SIMPLE_NIGHTSHADE_PROTOCOL_VERSION - 1
+    }
+}
+
+/// The `simple_nightshade_shard_layout` used throughout this file's tests: splits the single
+/// genesis shard into four, a fixed shard for `test0` and three shards bounded by `abc` and `foo`.
+fn simple_nightshade_shard_layout() -> ShardLayout {
+    ShardLayout::v1(
+        vec!["test0"].into_iter().map(|s| s.parse().unwrap()).collect(),
+        vec!["abc", "foo"].into_iter().map(|s| s.parse().unwrap()).collect(),
+        Some(vec![vec![0, 1, 2, 3]]),
+        1,
+    )
+}
+
+/// A three-way split at a different boundary than `simple_nightshade_shard_layout`, to exercise
+/// `TestShardUpgradeEnv` with a shard layout other than the one hardcoded constant.
+fn three_way_shard_layout() -> ShardLayout {
+    ShardLayout::v1(
+        vec![],
+        vec!["aaa", "mmm"].into_iter().map(|s| s.parse().unwrap()).collect(),
+        Some(vec![vec![0, 1, 2]]),
+        1,
+    )
+}
+
 fn setup_genesis(
     epoch_length: u64,
     num_validators: u64,
     initial_accounts: Vec<AccountId>,
     gas_limit: Option<u64>,
+    shard_layout: &ShardLayout,
 ) -> Genesis {
     let mut genesis = Genesis::test(initial_accounts, num_validators);
     // Set kickout threshold to 50 because chunks in the first block won't be produced (a known issue)
@@ -275,14 +473,7 @@ fn setup_genesis(
     genesis.config.chunk_producer_kickout_threshold = 50;
     genesis.config.epoch_length = epoch_length;
     genesis.config.protocol_version = SIMPLE_NIGHTSHADE_PROTOCOL_VERSION - 1;
-    let simple_nightshade_shard_layout = ShardLayout::v1(
-        vec!["test0"].into_iter().map(|s| s.parse().unwrap()).collect(),
-        vec!["abc", "foo"].into_iter().map(|s| s.parse().unwrap()).collect(),
-        Some(vec![vec![0, 1, 2, 3]]),
-        1,
-    );
-
-    genesis.config.simple_nightshade_shard_layout = Some(simple_nightshade_shard_layout.clone());
+    genesis.config.simple_nightshade_shard_layout = Some(shard_layout.clone());
 
     if let Some(gas_limit) = gas_limit {
         genesis.config.gas_limit = gas_limit;
@@ -300,7 +491,80 @@ fn test_shard_layout_upgrade_simple() {
 
     // setup
     let epoch_length = 5;
-    let mut test_env = TestShardUpgradeEnv::new(epoch_length, 2, 2, 100, None);
+    let mut test_env = TestShardUpgradeEnv::new(
+        epoch_length,
+        2,
+        2,
+        100,
+        None,
+        2,
+        simple_nightshade_shard_layout(),
+    );
+    test_env.set_init_tx(vec![]);
+
+    let mut nonce = 100;
+    let genesis_hash = test_env.env.clients[0].chain.genesis_block().hash().clone();
+    let mut all_accounts: HashSet<_> = test_env.initial_accounts.clone().into_iter().collect();
+    let signer0 = InMemorySigner::from_seed("test0".parse().unwrap(), KeyType::ED25519, "test0");
+    let generate_create_accounts_txs: &mut dyn FnMut(usize) -> Vec<SignedTransaction> =
+        &mut |max_size: usize| -> Vec<SignedTransaction> {
+            let size = rng.gen_range(0, max_size) + 1;
+            std::iter::repeat_with(|| loop {
+                let account_id = gen_account(&mut rng, b"abcdefghijkmn");
+                if all_accounts.insert(account_id.clone()) {
+                    let signer = InMemorySigner::from_seed(
+                        account_id.clone(),
+                        KeyType::ED25519,
+                        account_id.as_ref(),
+                    );
+                    let tx = SignedTransaction::create_account(
+                        nonce,
+                        signer0.account_id.clone(),
+                        account_id.clone(),
+                        NEAR_BASE,
+                        signer.public_key(),
+                        &signer0,
+                        genesis_hash.clone(),
+                    );
+                    nonce += 1;
+                    return tx;
+                }
+            })
+            .take(size)
+            .collect()
+        };
+
+    test_env.set_tx_at_height(epoch_length - 1, generate_create_accounts_txs(100));
+    test_env.set_tx_at_height(2 * epoch_length - 1, generate_create_accounts_txs(100));
+
+    for _ in 1..3 * epoch_length + 1 {
+        test_env.step();
+    }
+
+    test_env.check_accounts(&all_accounts.into_iter().collect::<Vec<_>>());
+    test_env.check_tx_outcomes();
+}
+
+// Same as `test_shard_layout_upgrade_simple`, but with a shard layout other than
+// `simple_nightshade_shard_layout`: a three-way split at a different account boundary, to check
+// that `TestShardUpgradeEnv` isn't secretly tied to the one hardcoded layout.
+#[test]
+fn test_shard_layout_upgrade_custom_layout() {
+    init_test_logger();
+
+    let mut rng = thread_rng();
+
+    // setup
+    let epoch_length = 5;
+    let mut test_env = TestShardUpgradeEnv::new(
+        epoch_length,
+        3,
+        3,
+        100,
+        None,
+        2,
+        three_way_shard_layout(),
+    );
     test_env.set_init_tx(vec![]);
 
     let mut nonce = 100;
@@ -415,7 +679,15 @@ fn test_shard_layout_upgrade_cross_contract_calls() {
 
     // setup
     let epoch_length = 5;
-    let mut test_env = TestShardUpgradeEnv::new(epoch_length, 4, 4, 100, Some(100_000_000_000_000));
+    let mut test_env = TestShardUpgradeEnv::new(
+        epoch_length,
+        4,
+        4,
+        100,
+        Some(100_000_000_000_000),
+        2,
+        simple_nightshade_shard_layout(),
+    );
 
     let genesis_hash = test_env.env.clients[0].chain.genesis_block().hash().clone();
     test_env.set_init_tx(
@@ -493,3 +765,186 @@ fn test_shard_layout_upgrade_cross_contract_calls() {
     test_env.check_tx_outcomes();
     test_env.check_accounts(&all_accounts.into_iter().collect::<Vec<_>>());
 }
+
+// Test that a node joining after the state split has happened can catch up to the post-split
+// shard layout via state sync rather than by reprocessing every historical block, with delayed
+// and postponed receipts still pending at the mid-epoch point the snapshot is taken.
+#[test]
+fn test_shard_layout_upgrade_late_joining_node() {
+    init_test_logger();
+
+    let epoch_length = 5;
+    let mut test_env = TestShardUpgradeEnv::new(
+        epoch_length,
+        4,
+        4,
+        100,
+        Some(100_000_000_000_000),
+        2,
+        simple_nightshade_shard_layout(),
+    );
+
+    let genesis_hash = test_env.env.clients[0].chain.genesis_block().hash().clone();
+    test_env.set_init_tx(
+        test_env.initial_accounts[0..test_env.num_validators]
+            .iter()
+            .map(|account_id| {
+                let signer = InMemorySigner::from_seed(
+                    account_id.clone(),
+                    KeyType::ED25519,
+                    &account_id.to_string(),
+                );
+                SignedTransaction::from_actions(
+                    1,
+                    account_id.clone(),
+                    account_id.clone(),
+                    &signer,
+                    vec![Action::DeployContract(DeployContractAction {
+                        code: near_test_contracts::rs_contract().to_vec(),
+                    })],
+                    genesis_hash.clone(),
+                )
+            })
+            .collect(),
+    );
+
+    let mut nonce = 100;
+    let mut rng = thread_rng();
+    let mut all_accounts: HashSet<_> = test_env.initial_accounts.clone().into_iter().collect();
+    let mut generate_txs = |size: usize| -> Vec<SignedTransaction> {
+        std::iter::repeat_with(|| loop {
+            let account_id = gen_account(&mut rng, b"abcdefghijkmn");
+            if all_accounts.insert(account_id.clone()) {
+                nonce += 1;
+                return gen_cross_contract_transaction(&account_id, nonce, &genesis_hash);
+            }
+        })
+        .take(size)
+        .collect()
+    };
+    // leave cross contract calls in flight at the epoch boundary so some receipts are still
+    // delayed/postponed when the snapshot is taken one block into epoch 2
+    test_env.set_tx_at_height(epoch_length, generate_txs(8));
+    test_env.set_tx_at_height(2 * epoch_length, generate_txs(8));
+
+    // step through the split (epoch 1) and one block into epoch 2, landing mid-epoch so that
+    // catchup for epoch 2 has not necessarily finished yet
+    for _ in 1..2 * epoch_length + 2 {
+        test_env.step();
+    }
+
+    // confirm the edge case actually landed: delayed receipts are still sitting in the trie at
+    // the point the late client's snapshot is taken. By this point the post-split layout is
+    // live, so look up shards through it rather than through the pre-split `ShardUId::default()`.
+    let client = &mut test_env.env.clients[0];
+    let block_hash = client.chain.head().unwrap().last_block_hash;
+    let block = client.chain.get_block(&block_hash).unwrap().clone();
+    let shard_layout = client
+        .runtime_adapter
+        .get_shard_layout_from_prev_block(block.header().prev_hash())
+        .unwrap();
+    let has_delayed_receipts = shard_layout.shard_ids().into_iter().any(|shard_id| {
+        let shard_uid = ShardUId::from_shard_id_and_layout(shard_id, &shard_layout);
+        let chunk_extra = client.chain.get_chunk_extra(&block_hash, &shard_uid).unwrap();
+        let trie_update = client
+            .runtime_adapter
+            .get_tries()
+            .new_trie_update_view(shard_uid, *chunk_extra.state_root());
+        let delayed_receipt_indices = get_delayed_receipt_indices(&trie_update).unwrap();
+        delayed_receipt_indices.first_index != delayed_receipt_indices.next_available_index
+    });
+    assert!(has_delayed_receipts, "expected some shard to still have delayed receipts pending");
+
+    test_env.add_late_client(true);
+}
+
+/// Timing and trie-size numbers for a single state-split run.
+#[derive(Serialize)]
+struct StateSplitBenchReport {
+    num_accounts: usize,
+    split_duration_millis: u128,
+    per_shard: Vec<ShardSplitStats>,
+}
+
+#[derive(Serialize)]
+struct ShardSplitStats {
+    shard_uid: ShardUId,
+    num_entries: usize,
+    total_bytes: usize,
+}
+
+/// Drives `test_env` through the state split and times just the split step, with the per-account
+/// sanity check `step` normally runs turned off so it doesn't dominate the measurement.
+fn measure_state_split(test_env: &mut TestShardUpgradeEnv) -> StateSplitBenchReport {
+    let num_accounts = test_env.initial_accounts.len();
+
+    // run up to, but not including, the last block of epoch 1, which is where the state split
+    // for the configured (single) transition actually happens
+    for _ in 1..2 * test_env.epoch_length {
+        test_env.step();
+    }
+    test_env.set_skip_account_checks(true);
+    let start = std::time::Instant::now();
+    test_env.step();
+    let split_duration_millis = start.elapsed().as_millis();
+    test_env.set_skip_account_checks(false);
+
+    let head = test_env.env.clients[0].chain.head().unwrap();
+    let block = test_env.env.clients[0].chain.get_block(&head.last_block_hash).unwrap().clone();
+    let shard_layout = test_env.env.clients[0]
+        .runtime_adapter
+        .get_shard_layout_from_prev_block(block.header().prev_hash())
+        .unwrap();
+
+    let mut per_shard = vec![];
+    for shard_id in shard_layout.shard_ids() {
+        let shard_uid = ShardUId::from_shard_id_and_layout(shard_id, &shard_layout);
+        let state_root = test_env.env.clients[0]
+            .chain
+            .get_chunk_extra(block.hash(), &shard_uid)
+            .unwrap()
+            .state_root()
+            .clone();
+        let chunks = produce_split_shard_snapshot(&mut test_env.env, 0, shard_uid, state_root);
+        let num_entries = chunks.iter().map(|chunk| chunk.entries.len()).sum();
+        let total_bytes = chunks
+            .iter()
+            .flat_map(|chunk| chunk.entries.iter())
+            .map(|(key, value)| key.len() + value.len())
+            .sum();
+        per_shard.push(ShardSplitStats { shard_uid, num_entries, total_bytes });
+    }
+
+    StateSplitBenchReport { num_accounts, split_duration_millis, per_shard }
+}
+
+/// Ad hoc state-split benchmark, not run as part of the normal suite; invoke explicitly with
+/// `cargo test --release -- --ignored bench_state_split`. Account count and gas limit are
+/// configurable via `BENCH_STATE_SPLIT_NUM_ACCOUNTS`/`BENCH_STATE_SPLIT_GAS_LIMIT`.
+#[test]
+#[ignore]
+fn bench_state_split() {
+    init_test_logger();
+
+    let num_accounts = std::env::var("BENCH_STATE_SPLIT_NUM_ACCOUNTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10_000);
+    let gas_limit =
+        std::env::var("BENCH_STATE_SPLIT_GAS_LIMIT").ok().and_then(|v| v.parse().ok());
+
+    let epoch_length = 5;
+    let mut test_env = TestShardUpgradeEnv::new(
+        epoch_length,
+        2,
+        2,
+        num_accounts,
+        gas_limit,
+        2,
+        simple_nightshade_shard_layout(),
+    );
+    test_env.set_init_tx(vec![]);
+
+    let report = measure_state_split(&mut test_env);
+    println!("{}", serde_json::to_string(&report).unwrap());
+}